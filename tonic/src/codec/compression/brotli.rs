@@ -0,0 +1,46 @@
+use std::io::{self, Write};
+
+use bytes::{BufMut, BytesMut};
+
+use super::{compression::BUFFER_SIZE, compressors::Compressor};
+
+/// Quality (0-11) used when compressing. Chosen as a balance between ratio and CPU cost rather
+/// than the crate's maximum.
+const QUALITY: u32 = 5;
+const LG_WINDOW_SIZE: u32 = 22;
+
+#[derive(Debug, Default)]
+pub(crate) struct Brotli;
+
+impl Compressor for Brotli {
+    fn name(&self) -> &'static str {
+        "br"
+    }
+
+    fn compress(
+        &self,
+        in_buffer: &mut BytesMut,
+        out_buffer: &mut BytesMut,
+        len: usize,
+    ) -> io::Result<()> {
+        let mut writer = brotli::CompressorWriter::new(
+            out_buffer.writer(),
+            BUFFER_SIZE,
+            QUALITY,
+            LG_WINDOW_SIZE,
+        );
+        writer.write_all(&in_buffer[..len])?;
+        writer.flush()
+    }
+
+    fn decompress(
+        &self,
+        in_buffer: &mut BytesMut,
+        out_buffer: &mut BytesMut,
+        len: usize,
+    ) -> io::Result<()> {
+        let mut reader = brotli::Decompressor::new(&in_buffer[..len], BUFFER_SIZE);
+        io::copy(&mut reader, &mut out_buffer.writer())?;
+        Ok(())
+    }
+}
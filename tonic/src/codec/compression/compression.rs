@@ -1,4 +1,9 @@
-use std::{fmt::Debug, io};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io,
+    sync::{Arc, Mutex, OnceLock},
+};
 
 use bytes::{Buf, BytesMut};
 use http::HeaderValue;
@@ -6,14 +11,23 @@ use tracing::debug;
 
 use crate::metadata::MetadataMap;
 
-use super::{Compressor, ENCODING_HEADER, compressors::{self, IDENTITY}};
+use super::{
+    Compressor, ENCODING_HEADER,
+    compressors::{self, EnabledCompressors, IDENTITY},
+};
 
 pub(crate) const BUFFER_SIZE: usize = 8 * 1024;
 pub(crate) const ACCEPT_ENCODING_HEADER: &str = "grpc-accept-encoding";
 
+/// Messages smaller than this are sent as identity rather than through the negotiated
+/// compressor, since framing overhead tends to outweigh any savings at this size.
+pub(crate) const DEFAULT_MIN_MESSAGE_SIZE: usize = 1024;
+
 #[derive(Clone)]
 pub(crate) struct Compression {
-    compressor: Option<&'static Box<dyn Compressor>>,
+    compressor: Option<Arc<dyn Compressor>>,
+    min_message_size: usize,
+    enabled: EnabledCompressors,
 }
 
 impl Debug for Compression {
@@ -21,56 +35,196 @@ impl Debug for Compression {
         f.debug_struct("Compression")
             .field(
                 "compressor",
-                &self.compressor.map(|c| c.name()).unwrap_or(IDENTITY),
+                &self.compressor.as_deref().map(Compressor::name).unwrap_or(IDENTITY),
             )
             .finish()
     }
 }
 
-fn parse_accept_encoding_header(value: &str) -> Vec<&str> {
+/// A single entry parsed out of a `grpc-accept-encoding` header value, e.g. `br;q=1.0` or a bare
+/// `gzip`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct AcceptEncoding<'a> {
+    name: &'a str,
+    q: f32,
+}
+
+fn parse_accept_encoding_header(value: &str) -> Vec<AcceptEncoding<'_>> {
     value
         .split(",")
-        .map(|v| v.trim())
-        .filter(|v| !v.is_empty())
+        .filter_map(|token| {
+            let token = token.trim();
+            if token.is_empty() {
+                return None;
+            }
+
+            let (name, q) = match token.split_once(";q=") {
+                // A malformed q isn't the same as a missing one: treat it as `q=0` (not acceptable)
+                // rather than silently promoting it to the top priority.
+                Some((name, q)) => (name.trim(), q.trim().parse::<f32>().unwrap_or(0.0)),
+                None => (token, 1.0),
+            };
+
+            // An explicit or defaulted `q=0` means "not acceptable", so drop the token entirely.
+            if q <= 0.0 {
+                None
+            } else {
+                Some(AcceptEncoding { name, q: q.min(1.0) })
+            }
+        })
         .collect::<Vec<_>>()
 }
 
-fn first_supported_compressor(accepted: &Vec<&str>) -> Option<&'static Box<dyn Compressor>> {
-    accepted
-        .iter()
-        .filter(|name| **name != IDENTITY)
-        .filter_map(|name| compressors::get(name))
-        .next()
+fn first_supported_compressor(
+    accepted: &[AcceptEncoding<'_>],
+    enabled: &EnabledCompressors,
+) -> Option<Arc<dyn Compressor>> {
+    let is_enabled = |name: &str| enabled.as_ref().is_none_or(|e| e.contains(&name));
+
+    let mut best: Option<(Arc<dyn Compressor>, f32)> = None;
+    let mut wildcard_q: Option<f32> = None;
+
+    for entry in accepted {
+        if entry.name == "*" {
+            // Keep the highest q if `*` is repeated, though that would be unusual.
+            wildcard_q = Some(wildcard_q.map_or(entry.q, |q| q.max(entry.q)));
+            continue;
+        }
+
+        if entry.name == IDENTITY || !is_enabled(entry.name) {
+            continue;
+        }
+
+        let Some(compressor) = compressors::get(entry.name) else {
+            continue;
+        };
+
+        let is_better = match &best {
+            Some((_, best_q)) => entry.q > *best_q,
+            None => true,
+        };
+
+        if is_better {
+            best = Some((compressor, entry.q));
+        }
+    }
+
+    match (best, wildcard_q) {
+        // A concrete codec only wins over the wildcard if it's strictly preferred.
+        (Some((compressor, q)), Some(wildcard_q)) if q > wildcard_q => Some(compressor),
+        (Some(_), Some(_)) => compressors::preferred(enabled),
+        (Some((compressor, _)), None) => Some(compressor),
+        (None, Some(_)) => compressors::preferred(enabled),
+        (None, None) => None,
+    }
+}
+
+/// How many distinct `(accept-encoding header, enabled set)` pairs [`negotiate`] remembers
+/// before it gives up and starts over, since real clients only ever send a handful of fixed
+/// header strings against a fixed, per-process enabled set.
+const NEGOTIATION_CACHE_CAPACITY: usize = 64;
+
+type NegotiationCacheKey = (String, EnabledCompressors);
+
+type NegotiationCache = Mutex<HashMap<NegotiationCacheKey, Option<Arc<dyn Compressor>>>>;
+
+fn negotiation_cache() -> &'static NegotiationCache {
+    static CACHE: OnceLock<NegotiationCache> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Resolve the compressor to use for `accept_encoding_header`, memoized on the header value and
+/// the locally `enabled` set so repeated identical headers skip parsing and negotiation.
+fn negotiate(accept_encoding_header: &str, enabled: &EnabledCompressors) -> Option<Arc<dyn Compressor>> {
+    let key = (accept_encoding_header.to_owned(), enabled.clone());
+    let cache = negotiation_cache();
+
+    if let Some(compressor) = cache.lock().unwrap().get(&key) {
+        return compressor.clone();
+    }
+
+    let parsed = parse_accept_encoding_header(accept_encoding_header);
+    let compressor = first_supported_compressor(&parsed, enabled);
+
+    let mut cache = cache.lock().unwrap();
+    // Real traffic only ever exercises a handful of distinct (header, enabled set) pairs; if
+    // something unexpected blows past that, drop the cache rather than let it grow without bound.
+    if cache.len() >= NEGOTIATION_CACHE_CAPACITY {
+        cache.clear();
+    }
+    cache.insert(key, compressor.clone());
+
+    compressor
 }
 
 impl Compression {
     /// Create an instance of compression that doesn't compress anything
     pub(crate) fn disabled() -> Compression {
-        Compression { compressor: None }
+        Compression {
+            compressor: None,
+            min_message_size: DEFAULT_MIN_MESSAGE_SIZE,
+            enabled: None,
+        }
     }
 
-    /// Create an instance of compression from GRPC metadata
-    pub(crate) fn response_from_metadata(request_metadata: &MetadataMap) -> Compression {
+    /// Create an instance of compression that always sends with `name`, regardless of what the
+    /// peer advertises support for. Falls back to [`disabled`](Self::disabled) if `name` isn't a
+    /// registered compressor.
+    pub(crate) fn with_compressor(name: &str) -> Compression {
+        Compression {
+            compressor: compressors::get(name),
+            min_message_size: DEFAULT_MIN_MESSAGE_SIZE,
+            enabled: None,
+        }
+    }
+
+    /// Create an instance of compression that always sends zstd at the given level, regardless
+    /// of what the peer advertises support for.
+    #[cfg(feature = "zstd")]
+    pub(crate) fn with_zstd_level(level: i32) -> Compression {
+        Compression {
+            compressor: Some(Arc::new(super::zstd::Zstd::with_level(level)) as Arc<dyn Compressor>),
+            min_message_size: DEFAULT_MIN_MESSAGE_SIZE,
+            enabled: None,
+        }
+    }
+
+    /// Create an instance of compression from GRPC metadata, only negotiating compressors in
+    /// `enabled` (all registered compressors, if `None`).
+    pub(crate) fn response_from_metadata(
+        request_metadata: &MetadataMap,
+        enabled: EnabledCompressors,
+    ) -> Compression {
         let accept_encoding_header = request_metadata
             .get(ACCEPT_ENCODING_HEADER)
             .and_then(|v| v.to_str().ok())
             .unwrap_or("");
 
-        let parsed = parse_accept_encoding_header(accept_encoding_header);
-        let compressor = first_supported_compressor(&parsed);
-        Compression { compressor }
+        let compressor = negotiate(accept_encoding_header, &enabled);
+        Compression {
+            compressor,
+            min_message_size: DEFAULT_MIN_MESSAGE_SIZE,
+            enabled,
+        }
     }
 
-    /// Create an instance of compression from HTTP headers
-    pub(crate) fn response_from_headers(request_headers: &http::HeaderMap) -> Compression {
+    /// Create an instance of compression from HTTP headers, only negotiating compressors in
+    /// `enabled` (all registered compressors, if `None`).
+    pub(crate) fn response_from_headers(
+        request_headers: &http::HeaderMap,
+        enabled: EnabledCompressors,
+    ) -> Compression {
         let accept_encoding_header = request_headers
             .get(ACCEPT_ENCODING_HEADER)
             .and_then(|v| v.to_str().ok())
             .unwrap_or("");
 
-        let parsed = parse_accept_encoding_header(accept_encoding_header);
-        let compressor = first_supported_compressor(&parsed);
-        Compression { compressor }
+        let compressor = negotiate(accept_encoding_header, &enabled);
+        Compression {
+            compressor,
+            min_message_size: DEFAULT_MIN_MESSAGE_SIZE,
+            enabled,
+        }
     }
 
     /// Get if compression is enabled
@@ -78,32 +232,46 @@ impl Compression {
         self.compressor.is_some()
     }
 
-    /// Decompress `len` bytes from `in_buffer` into `out_buffer`
+    /// Set the minimum message size, in bytes, below which messages are sent as identity
+    /// instead of through the negotiated compressor.
+    pub(crate) fn set_min_message_size(&mut self, min_message_size: usize) {
+        self.min_message_size = min_message_size;
+    }
+
+    /// Compress `len` bytes from `in_buffer` into `out_buffer`, returning whether the
+    /// negotiated compressor was actually used. Messages under `min_message_size` are sent as
+    /// identity regardless of negotiation, so the caller must use the returned flag (rather
+    /// than assuming `is_enabled()`) to mark the frame's compressed bit.
     pub(crate) fn compress(
         &self,
         in_buffer: &mut BytesMut,
         out_buffer: &mut BytesMut,
         len: usize,
-    ) -> Result<(), io::Error> {
+    ) -> Result<bool, io::Error> {
         out_buffer.reserve(((len / BUFFER_SIZE) + 1) * BUFFER_SIZE);
 
-        let compressor = self.compressor.unwrap_or_else(compressors::identity);
+        let compressor = if len >= self.min_message_size {
+            self.compressor.clone()
+        } else {
+            None
+        };
+        let compressor = compressor.unwrap_or_else(compressors::identity);
         compressor.compress(in_buffer, out_buffer, len)?;
         in_buffer.advance(len);
 
         debug!(
-            "Decompressed {} bytes into {} bytes using {:?}",
+            "Compressed {} bytes into {} bytes using {:?}",
             len,
             out_buffer.len(),
             compressor.name()
         );
 
-        Ok(())
+        Ok(compressor.name() != compressors::IDENTITY)
     }
 
     /// Set the `grpc-encoding` header with the compressor name
     pub(crate) fn set_headers(&self, headers: &mut http::HeaderMap) {
-        match self.compressor {
+        match &self.compressor {
             None => {},
             Some(compressor) => {
                 headers.insert(ENCODING_HEADER, HeaderValue::from_static(compressor.name()));
@@ -111,3 +279,158 @@ impl Compression {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_missing_q_to_one() {
+        let parsed = parse_accept_encoding_header("gzip, br");
+        assert_eq!(
+            parsed,
+            vec![
+                AcceptEncoding { name: "gzip", q: 1.0 },
+                AcceptEncoding { name: "br", q: 1.0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_explicit_q_values() {
+        let parsed = parse_accept_encoding_header("br;q=1.0, gzip;q=0.8, *;q=0.1");
+        assert_eq!(
+            parsed,
+            vec![
+                AcceptEncoding { name: "br", q: 1.0 },
+                AcceptEncoding { name: "gzip", q: 0.8 },
+                AcceptEncoding { name: "*", q: 0.1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_tokens_with_q_zero() {
+        let parsed = parse_accept_encoding_header("gzip;q=0, br;q=0.5");
+        assert_eq!(parsed, vec![AcceptEncoding { name: "br", q: 0.5 }]);
+    }
+
+    #[test]
+    fn drops_tokens_with_unparseable_q() {
+        let parsed = parse_accept_encoding_header("gzip;q=banana, br;q=0.5");
+        assert_eq!(parsed, vec![AcceptEncoding { name: "br", q: 0.5 }]);
+    }
+
+    #[test]
+    fn unsupported_codec_without_wildcard_yields_no_compressor() {
+        let parsed = parse_accept_encoding_header("made-up-codec;q=1.0");
+        assert!(first_supported_compressor(&parsed, &None).is_none());
+    }
+
+    #[test]
+    fn with_compressor_falls_back_to_disabled_for_unregistered_name() {
+        let compression = Compression::with_compressor("made-up-codec");
+        assert!(!compression.is_enabled());
+    }
+
+    #[cfg(all(feature = "gzip", feature = "brotli"))]
+    #[test]
+    fn enabled_set_excludes_a_codec_present_in_the_header() {
+        let parsed = parse_accept_encoding_header("br;q=1.0, gzip;q=0.5");
+        let compressor = first_supported_compressor(&parsed, &Some(vec!["gzip"])).unwrap();
+        assert_eq!(compressor.name(), "gzip");
+    }
+
+    #[cfg(all(feature = "gzip", feature = "brotli"))]
+    #[test]
+    fn picks_the_highest_q_even_when_listed_later() {
+        let parsed = parse_accept_encoding_header("gzip;q=0.5, br;q=1.0");
+        let compressor = first_supported_compressor(&parsed, &None).unwrap();
+        assert_eq!(compressor.name(), "br");
+    }
+
+    #[cfg(all(feature = "gzip", feature = "brotli"))]
+    #[test]
+    fn ties_break_by_the_order_the_client_listed_them() {
+        let parsed = parse_accept_encoding_header("gzip;q=0.8, br;q=0.8");
+        let compressor = first_supported_compressor(&parsed, &None).unwrap();
+        assert_eq!(compressor.name(), "gzip");
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn a_better_concrete_codec_beats_the_wildcard() {
+        let parsed = parse_accept_encoding_header("gzip;q=1.0, *;q=0.5");
+        let compressor = first_supported_compressor(&parsed, &None).unwrap();
+        assert_eq!(compressor.name(), "gzip");
+    }
+
+    #[cfg(all(feature = "gzip", feature = "brotli"))]
+    #[test]
+    fn wildcard_winning_falls_back_to_the_preferred_compressor() {
+        let parsed = parse_accept_encoding_header("br;q=0.1, *;q=1.0");
+        let compressor = first_supported_compressor(&parsed, &None).unwrap();
+        // The wildcard outranks the explicitly (lowly) preferred `br`, so negotiation falls back
+        // to whichever compressor the server would pick on its own: `gzip`, per PREFERENCE_ORDER.
+        assert_eq!(compressor.name(), "gzip");
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn compress_below_min_message_size_forces_identity() {
+        let mut compression = Compression::with_compressor("gzip");
+        compression.set_min_message_size(1024);
+
+        let mut in_buffer = BytesMut::from(&[0u8; 16][..]);
+        let mut out_buffer = BytesMut::new();
+
+        let used_compressor = compression
+            .compress(&mut in_buffer, &mut out_buffer, 16)
+            .unwrap();
+
+        assert!(!used_compressor);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn compress_at_min_message_size_uses_negotiated_compressor() {
+        let mut compression = Compression::with_compressor("gzip");
+        compression.set_min_message_size(16);
+
+        let mut in_buffer = BytesMut::from(&[0u8; 16][..]);
+        let mut out_buffer = BytesMut::new();
+
+        let used_compressor = compression
+            .compress(&mut in_buffer, &mut out_buffer, 16)
+            .unwrap();
+
+        assert!(used_compressor);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn same_header_different_enabled_set_yields_different_result() {
+        let header = "gzip;q=1.0";
+
+        let with_gzip_enabled = negotiate(header, &Some(vec!["gzip"]));
+        assert_eq!(with_gzip_enabled.unwrap().name(), "gzip");
+
+        let with_gzip_disabled = negotiate(header, &Some(vec!["br"]));
+        assert!(with_gzip_disabled.is_none());
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn negotiation_cache_stays_bounded_across_distinct_keys() {
+        // Insert more distinct (header, enabled) pairs than the cache's capacity and make sure it
+        // doesn't grow past that bound instead of accumulating forever.
+        for i in 0..(NEGOTIATION_CACHE_CAPACITY * 2) {
+            let header = format!("gzip;q=1.0, unused-{i}");
+            let enabled = Some(vec!["gzip"]);
+            negotiate(&header, &enabled);
+        }
+
+        let cache = negotiation_cache().lock().unwrap();
+        assert!(cache.len() <= NEGOTIATION_CACHE_CAPACITY);
+    }
+}
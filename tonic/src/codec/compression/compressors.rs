@@ -0,0 +1,112 @@
+use std::{collections::HashMap, fmt::Debug, io, sync::Arc, sync::OnceLock};
+
+use bytes::BytesMut;
+
+pub(crate) const IDENTITY: &str = "identity";
+
+/// The set of compressor names an application is willing to emit. `None` means any registered
+/// compressor is allowed; `Some` restricts negotiation to the given names.
+pub(crate) type EnabledCompressors = Option<Vec<&'static str>>;
+
+/// Preference order used to pick a compressor when the peer leaves the choice up to us, e.g. a
+/// bare `*` wildcard in `grpc-accept-encoding`.
+const PREFERENCE_ORDER: &[&str] = &["gzip", "br", "zstd"];
+
+/// A gRPC message compressor/decompressor.
+///
+/// Implementations are registered in [`get`] and selected based on the `grpc-accept-encoding`
+/// header sent by the peer.
+pub(crate) trait Compressor: Debug + Send + Sync + 'static {
+    /// The name used in the `grpc-encoding` / `grpc-accept-encoding` headers, e.g. `"gzip"`.
+    fn name(&self) -> &'static str;
+
+    /// Compress `len` bytes from `in_buffer` into `out_buffer`.
+    fn compress(
+        &self,
+        in_buffer: &mut BytesMut,
+        out_buffer: &mut BytesMut,
+        len: usize,
+    ) -> io::Result<()>;
+
+    /// Decompress `len` bytes from `in_buffer` into `out_buffer`.
+    fn decompress(
+        &self,
+        in_buffer: &mut BytesMut,
+        out_buffer: &mut BytesMut,
+        len: usize,
+    ) -> io::Result<()>;
+}
+
+#[derive(Debug, Default)]
+struct Identity;
+
+impl Compressor for Identity {
+    fn name(&self) -> &'static str {
+        IDENTITY
+    }
+
+    fn compress(
+        &self,
+        in_buffer: &mut BytesMut,
+        out_buffer: &mut BytesMut,
+        len: usize,
+    ) -> io::Result<()> {
+        out_buffer.extend_from_slice(&in_buffer[..len]);
+        Ok(())
+    }
+
+    fn decompress(
+        &self,
+        in_buffer: &mut BytesMut,
+        out_buffer: &mut BytesMut,
+        len: usize,
+    ) -> io::Result<()> {
+        out_buffer.extend_from_slice(&in_buffer[..len]);
+        Ok(())
+    }
+}
+
+fn registry() -> &'static HashMap<&'static str, Arc<dyn Compressor>> {
+    static REGISTRY: OnceLock<HashMap<&'static str, Arc<dyn Compressor>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        #[allow(unused_mut)]
+        let mut compressors: HashMap<&'static str, Arc<dyn Compressor>> = HashMap::new();
+
+        #[cfg(feature = "gzip")]
+        compressors.insert("gzip", Arc::new(super::gzip::Gzip::default()) as Arc<dyn Compressor>);
+
+        #[cfg(feature = "brotli")]
+        compressors.insert("br", Arc::new(super::brotli::Brotli) as Arc<dyn Compressor>);
+
+        #[cfg(feature = "zstd")]
+        compressors.insert(
+            "zstd",
+            Arc::new(super::zstd::Zstd::default()) as Arc<dyn Compressor>,
+        );
+
+        compressors
+    })
+}
+
+/// Look up a registered compressor by its `grpc-encoding` name.
+pub(crate) fn get(name: &str) -> Option<Arc<dyn Compressor>> {
+    registry().get(name).cloned()
+}
+
+/// The identity (no-op) compressor, used when no compression is negotiated.
+pub(crate) fn identity() -> Arc<dyn Compressor> {
+    static IDENTITY_COMPRESSOR: OnceLock<Arc<dyn Compressor>> = OnceLock::new();
+    IDENTITY_COMPRESSOR
+        .get_or_init(|| Arc::new(Identity))
+        .clone()
+}
+
+/// The compressor the server would pick absent any preference from the peer, used as the
+/// fallback when a `*` wildcard wins the `grpc-accept-encoding` negotiation. Restricted to
+/// `enabled` when it's set.
+pub(crate) fn preferred(enabled: &EnabledCompressors) -> Option<Arc<dyn Compressor>> {
+    PREFERENCE_ORDER
+        .iter()
+        .filter(|name| enabled.as_ref().is_none_or(|e| e.contains(*name)))
+        .find_map(|name| get(name))
+}
@@ -7,6 +7,12 @@ mod errors;
 #[cfg(feature = "gzip")]
 mod gzip;
 
+#[cfg(feature = "brotli")]
+mod brotli;
+
+#[cfg(feature = "zstd")]
+mod zstd;
+
 pub(crate) use self::compressors::Compressor;
 
 #[doc(hidden)]
@@ -0,0 +1,56 @@
+use std::io::{self, Write};
+
+use bytes::{BufMut, BytesMut};
+
+use super::compressors::Compressor;
+
+/// A balanced default: noticeably faster than zstd's higher levels while still beating gzip's
+/// ratio on typical protobuf payloads.
+const DEFAULT_LEVEL: i32 = 3;
+
+#[derive(Debug)]
+pub(crate) struct Zstd {
+    level: i32,
+}
+
+impl Default for Zstd {
+    fn default() -> Self {
+        Zstd {
+            level: DEFAULT_LEVEL,
+        }
+    }
+}
+
+impl Zstd {
+    /// Create a zstd compressor using the given compression level, trading ratio for speed.
+    pub(crate) fn with_level(level: i32) -> Self {
+        Zstd { level }
+    }
+}
+
+impl Compressor for Zstd {
+    fn name(&self) -> &'static str {
+        "zstd"
+    }
+
+    fn compress(
+        &self,
+        in_buffer: &mut BytesMut,
+        out_buffer: &mut BytesMut,
+        len: usize,
+    ) -> io::Result<()> {
+        let mut writer = zstd::Encoder::new(out_buffer.writer(), self.level)?.auto_finish();
+        writer.write_all(&in_buffer[..len])
+    }
+
+    fn decompress(
+        &self,
+        in_buffer: &mut BytesMut,
+        out_buffer: &mut BytesMut,
+        len: usize,
+    ) -> io::Result<()> {
+        let mut decoder = zstd::Decoder::new(&in_buffer[..len])?;
+        io::copy(&mut decoder, &mut out_buffer.writer())?;
+        Ok(())
+    }
+}